@@ -1,17 +1,73 @@
-use anyhow::Context;
-use camino::Utf8PathBuf;
+use anyhow::{format_err, Context};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine as _;
+use camino::{Utf8Path, Utf8PathBuf};
+use configparser::ini::Ini;
 use fs_err as fs;
+use pep440_rs::Version as Pep440Version;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use std::io;
-use std::io::BufWriter;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tempfile::NamedTempFile;
 use tracing::info;
 
-pub fn download_wheel_cached(filename: &str, url: &str) -> anyhow::Result<Utf8PathBuf> {
+/// Parse the `#sha256=...` fragment PyPI commonly appends to download URLs.
+#[cfg(not(feature = "install"))]
+fn sha256_from_url_fragment(url: &str) -> Option<String> {
+    url.rsplit_once('#')?
+        .1
+        .strip_prefix("sha256=")
+        .map(str::to_string)
+}
+
+/// Download a wheel to the local cache, or reuse it if already cached.
+///
+/// `expected_sha256` (or, if not given, a `#sha256=...` fragment on `url`) is
+/// checked against a streaming hash of the downloaded bytes before the
+/// tempfile is persisted, so a truncated or tampered download never becomes
+/// a cached wheel; a mismatch leaves the cache untouched and returns an
+/// error. A cache hit is re-verified the same way, so a corrupted cache
+/// entry is detected and re-downloaded instead of trusted.
+#[cfg(not(feature = "install"))]
+pub fn download_wheel_cached(
+    filename: &str,
+    url: &str,
+    expected_sha256: Option<&str>,
+) -> anyhow::Result<Utf8PathBuf> {
+    let expected_sha256 = expected_sha256
+        .map(str::to_string)
+        .or_else(|| sha256_from_url_fragment(url));
+    // PyPI publishes hex digests, while `sha256_file` and `HashingWriter`
+    // produce the RECORD-style URL-safe base64 form, so convert before comparing.
+    let expected_sha256 = match expected_sha256 {
+        Some(hex) => {
+            let bytes = (0..hex.len())
+                .step_by(2)
+                .map(|i| hex.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+                .collect::<Option<Vec<u8>>>()
+                .with_context(|| format!("Expected sha256 {hex} is not a hex digest"))?;
+            Some(URL_SAFE_NO_PAD.encode(bytes))
+        }
+        None => None,
+    };
+
     let wheels_cache = crate::crate_cache_dir()?.join("wheels");
     let cached_wheel = wheels_cache.join(filename);
     if cached_wheel.is_file() {
-        info!("Using cached wheel at {cached_wheel}");
-        return Ok(cached_wheel);
+        match &expected_sha256 {
+            Some(expected) if sha256_file(&cached_wheel)?.0 != *expected => {
+                info!("Cached wheel at {cached_wheel} failed hash verification, re-downloading");
+            }
+            _ => {
+                info!("Using cached wheel at {cached_wheel}");
+                return Ok(cached_wheel);
+            }
+        }
     }
 
     info!("Downloading wheel from {url} to {cached_wheel}");
@@ -19,66 +75,469 @@ pub fn download_wheel_cached(filename: &str, url: &str) -> anyhow::Result<Utf8Pa
     let mut tempfile = NamedTempFile::new_in(wheels_cache)?;
     let tempfile_path = tempfile.path().to_path_buf();
     let mut response = minreq::get(url).send_lazy()?;
-    io::copy(&mut response, &mut BufWriter::new(&mut tempfile)).with_context(|| {
+    let mut hashing = HashingWriter::new(BufWriter::new(&mut tempfile));
+    io::copy(&mut response, &mut hashing).with_context(|| {
         format!(
             "Failed to download wheel from {} to {}",
             url,
             tempfile_path.display()
         )
     })?;
+    let (actual_sha256, _) = hashing.finish();
+    if let Some(expected) = &expected_sha256 {
+        if &actual_sha256 != expected {
+            return Err(format_err!(
+                "Downloaded wheel from {url} has sha256 {actual_sha256}, expected {expected}"
+            ));
+        }
+    }
     tempfile
         .persist(&cached_wheel)
         .with_context(|| format!("Failed to persist tempfile to {}", cached_wheel))?;
     Ok(cached_wheel)
 }
 
-/// Install wheel, pip and setuptools from the cache
+/// Target platform for an [`InstallPaths`] scheme.
+#[cfg(not(feature = "install"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Platform {
+    Unix,
+    Windows,
+}
+
+/// Where a wheel's files end up once installed — a virtualenv's "scheme", in
+/// distutils/pip terminology. Covers the five directories a wheel's payload
+/// can target: `purelib`, `platlib`, `scripts`, `data` and `include`, plus
+/// the `.data/` subdirectory names the wheel spec uses for each
+/// (`purelib`, `platlib`, `scripts`, `data`, `headers`).
+#[cfg(not(feature = "install"))]
+pub struct InstallPaths {
+    /// Root of the virtualenv. `RECORD` paths are stored relative to this.
+    pub root: Utf8PathBuf,
+    /// Where pure-Python packages and dist-info directories are unpacked to.
+    pub purelib: Utf8PathBuf,
+    /// Where platform-specific (compiled extension) packages are unpacked
+    /// to. Same as `purelib` for the venvs we create.
+    pub platlib: Utf8PathBuf,
+    /// Directory console script launchers are written to (`bin` on Unix,
+    /// `Scripts` on Windows).
+    pub scripts: Utf8PathBuf,
+    /// Root for a wheel's `.data/data/` payload — arbitrary files installed
+    /// relative to the venv root.
+    pub data: Utf8PathBuf,
+    /// Root for a wheel's `.data/headers/` payload (C headers).
+    pub include: Utf8PathBuf,
+    /// Path to the venv's python interpreter, used as the launcher shebang.
+    pub python: Utf8PathBuf,
+}
+
+#[cfg(not(feature = "install"))]
+impl InstallPaths {
+    /// Compute the scheme for a virtualenv at `root`, targeting the given
+    /// Python `(major, minor)` version and `platform`.
+    pub fn new(
+        root: &Utf8Path,
+        python: &Utf8Path,
+        python_version: (u8, u8),
+        platform: Platform,
+    ) -> Self {
+        let (major, minor) = python_version;
+        match platform {
+            Platform::Unix => {
+                let site_packages = root
+                    .join("lib")
+                    .join(format!("python{major}.{minor}"))
+                    .join("site-packages");
+                InstallPaths {
+                    root: root.to_path_buf(),
+                    purelib: site_packages.clone(),
+                    platlib: site_packages,
+                    scripts: root.join("bin"),
+                    data: root.to_path_buf(),
+                    include: root.join("include"),
+                    python: python.to_path_buf(),
+                }
+            }
+            Platform::Windows => {
+                let site_packages = root.join("Lib").join("site-packages");
+                InstallPaths {
+                    root: root.to_path_buf(),
+                    purelib: site_packages.clone(),
+                    platlib: site_packages,
+                    scripts: root.join("Scripts"),
+                    data: root.to_path_buf(),
+                    include: root.join("Include"),
+                    python: python.to_path_buf(),
+                }
+            }
+        }
+    }
+
+    /// Map a wheel's `{name}-{version}.data/{key}/` directory name to the
+    /// scheme directory its contents should be installed into.
+    fn data_scheme_dir(&self, key: &str) -> Option<&Utf8Path> {
+        match key {
+            "purelib" => Some(&self.purelib),
+            "platlib" => Some(&self.platlib),
+            "scripts" => Some(&self.scripts),
+            "data" => Some(&self.data),
+            "headers" => Some(&self.include),
+            _ => None,
+        }
+    }
+}
+
+/// A single row of a `RECORD` file: an installed file's path, and its hash
+/// and size unless it's exempt (the `RECORD` file itself and launcher
+/// scripts, which the wheel spec allows to leave blank).
+#[cfg(not(feature = "install"))]
+struct RecordEntry {
+    path: Utf8PathBuf,
+    hash: Option<String>,
+    size: Option<u64>,
+}
+
+/// Strip `root` off an absolute path, for storing `RECORD` paths relative to
+/// the install root as the wheel spec requires.
+///
+/// `Utf8PathBuf::join` uses the host's native separator, so on Windows the
+/// result would otherwise contain backslashes; the `RECORD` format requires
+/// `/`-delimited paths on every platform, so we normalize here rather than
+/// at each call site.
+#[cfg(not(feature = "install"))]
+fn root_relative(root: &Utf8Path, path: &Utf8Path) -> Utf8PathBuf {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    Utf8PathBuf::from(relative.as_str().replace('\\', "/"))
+}
+
+/// Join a `/`-delimited `RECORD` path back onto `root` to get a filesystem
+/// path, going through `Utf8Path::join` component by component so the
+/// result uses the host's native separator even though `record_path` always
+/// uses `/`.
+#[cfg(not(feature = "install"))]
+fn record_path_to_fs(root: &Utf8Path, record_path: &str) -> Utf8PathBuf {
+    record_path
+        .split('/')
+        .fold(root.to_path_buf(), |acc, part| acc.join(part))
+}
+
+/// Resolve where a single wheel archive member should be extracted to: a
+/// `{name}-{version}.data/{key}/...` entry is routed to the scheme directory
+/// `key` maps to, everything else lands in `purelib`.
+#[cfg(not(feature = "install"))]
+fn wheel_target(
+    paths: &InstallPaths,
+    data_dir_name: &str,
+    relative: &Utf8Path,
+) -> anyhow::Result<Utf8PathBuf> {
+    let Ok(under_data) = relative.strip_prefix(data_dir_name) else {
+        return Ok(paths.purelib.join(relative));
+    };
+    let mut components = under_data.components();
+    let key = components
+        .next()
+        .with_context(|| format!("{data_dir_name} has an entry with no scheme directory"))?;
+    let rest = components.as_path();
+    let scheme_dir = paths
+        .data_scheme_dir(key.as_str())
+        .with_context(|| format!("Unknown wheel data directory {data_dir_name}/{key}"))?;
+    Ok(scheme_dir.join(rest))
+}
+
+/// A writer that hashes everything written to it with SHA-256 while
+/// forwarding it to an inner writer, so an installed file can be hashed in
+/// the same streaming pass that writes it instead of being re-read
+/// afterwards.
+#[cfg(not(feature = "install"))]
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+    len: u64,
+}
+
+#[cfg(not(feature = "install"))]
+impl<W: io::Write> HashingWriter<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            len: 0,
+        }
+    }
+
+    fn finish(self) -> (String, u64) {
+        (encode_digest(self.hasher), self.len)
+    }
+}
+
+#[cfg(not(feature = "install"))]
+impl<W: io::Write> io::Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.len += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Encode a finished SHA-256 digest the way `RECORD` requires: URL-safe
+/// base64 with the trailing `=` padding stripped.
+#[cfg(not(feature = "install"))]
+fn encode_digest(hasher: Sha256) -> String {
+    URL_SAFE_NO_PAD.encode(hasher.finalize())
+}
+
+/// Stream-hash a file's contents with SHA-256, reading in fixed-size chunks
+/// so large files never need to be fully buffered.
+#[cfg(not(feature = "install"))]
+fn sha256_file(path: &Utf8Path) -> anyhow::Result<(String, u64)> {
+    let mut file = fs::File::open(path).with_context(|| format!("Failed to open {path}"))?;
+    let mut hasher = Sha256::new();
+    let mut len = 0u64;
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+        len += read as u64;
+    }
+    Ok((encode_digest(hasher), len))
+}
+
+/// Re-hash every file listed in `{dist_info}/RECORD` against its current
+/// contents and report the paths whose hash no longer matches.
 #[cfg(not(feature = "install"))]
-fn install_base_packages(
+pub fn verify_record(dist_info: &Utf8Path, root: &Utf8Path) -> anyhow::Result<Vec<Utf8PathBuf>> {
+    let record_path = dist_info.join("RECORD");
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_path(&record_path)
+        .with_context(|| format!("Failed to read {record_path}"))?;
+
+    let mut mismatched = Vec::new();
+    for result in reader.records() {
+        let record = result.with_context(|| format!("{record_path} is not a valid RECORD csv"))?;
+        let (Some(path), Some(hash_field)) = (record.get(0), record.get(1)) else {
+            continue;
+        };
+        let Some(expected) = hash_field.strip_prefix("sha256=") else {
+            continue;
+        };
+        let (actual, _) = sha256_file(&record_path_to_fs(root, path))?;
+        if actual != expected {
+            mismatched.push(Utf8PathBuf::from(path));
+        }
+    }
+    Ok(mismatched)
+}
+
+/// Install a wheel by streaming its zip entries directly to their
+/// destination, mirroring how pip's `wheel.py` walks archive members and
+/// dispatches each to its target location.
+///
+/// Locates the `{name}-{version}.dist-info/` directory inside the archive,
+/// extracts every member to its scheme directory (`paths.purelib` by
+/// default, or the scheme `InstallPaths` maps a `{name}-{version}.data/{key}/`
+/// entry to), then generates console and GUI script launchers and writes the
+/// `RECORD` file. Returns the path to the installed dist-info directory.
+///
+/// `compile_bytecode` controls whether the extracted `.py` files are
+/// byte-compiled to `.pyc` afterwards (pip's `--compile`, on by default).
+#[cfg(not(feature = "install"))]
+pub fn install_wheel(
+    wheel_path: &Utf8Path,
+    paths: &InstallPaths,
+    compile_bytecode: bool,
+) -> anyhow::Result<Utf8PathBuf> {
+    let file = fs::File::open(wheel_path)
+        .with_context(|| format!("Failed to open wheel at {wheel_path}"))?;
+    let mut archive = zip::ZipArchive::new(io::BufReader::new(file))
+        .with_context(|| format!("{wheel_path} is not a valid zip archive"))?;
+
+    let dist_info = (0..archive.len())
+        .map(|i| -> anyhow::Result<String> { Ok(archive.by_index(i)?.name().to_string()) })
+        .collect::<anyhow::Result<Vec<_>>>()?
+        .into_iter()
+        .find_map(|name| {
+            let (dir, _) = name.split_once('/')?;
+            dir.ends_with(".dist-info").then(|| dir.to_string())
+        })
+        .with_context(|| format!("{wheel_path} has no *.dist-info directory"))?;
+    let data_dir_name = format!(
+        "{}.data",
+        dist_info
+            .strip_suffix(".dist-info")
+            .context("dist-info directory name is malformed")?
+    );
+
+    let mut installed = Vec::new();
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        if entry.is_dir() {
+            continue;
+        }
+        let Some(relative) = entry.enclosed_name() else {
+            continue;
+        };
+        let relative = Utf8PathBuf::from_path_buf(relative.to_path_buf())
+            .map_err(|path| format_err!("{path:?} is not valid UTF-8"))?;
+        let target = wheel_target(paths, &data_dir_name, &relative)?;
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut out = HashingWriter::new(fs::File::create(&target)?);
+        io::copy(&mut entry, &mut out)
+            .with_context(|| format!("Failed to extract {relative} from {wheel_path}"))?;
+        #[cfg(target_family = "unix")]
+        if let Some(mode) = entry.unix_mode() {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&target, std::fs::Permissions::from_mode(mode))?;
+        }
+        let (hash, size) = out.finish();
+        installed.push(RecordEntry {
+            path: root_relative(&paths.root, &target),
+            hash: Some(hash),
+            size: Some(size),
+        });
+    }
+
+    let dist_info_dir = paths.purelib.join(&dist_info);
+    let launchers = generate_entry_point_launchers(&dist_info_dir, &paths.scripts, &paths.python)?;
+    installed.extend(launchers.into_iter().map(|launcher| RecordEntry {
+        path: root_relative(&paths.root, &launcher),
+        hash: None,
+        size: None,
+    }));
+
+    if compile_bytecode {
+        let py_files: Vec<_> = installed
+            .iter()
+            .filter(|entry| entry.path.extension() == Some("py"))
+            .map(|entry| paths.root.join(&entry.path))
+            .collect();
+        for pyc in compile_pyc(&paths.python, &py_files)? {
+            let (hash, size) = sha256_file(&pyc)?;
+            installed.push(RecordEntry {
+                path: root_relative(&paths.root, &pyc),
+                hash: Some(hash),
+                size: Some(size),
+            });
+        }
+    }
+
+    write_record(&dist_info_dir, &paths.root, &installed)?;
+
+    Ok(dist_info_dir)
+}
+
+/// Byte-compile `py_files` to `.pyc`, using the venv's own interpreter so the
+/// PEP 3147 `__pycache__/{module}.{tag}.pyc` layout matches its cache tag.
+///
+/// The file list is passed on the interpreter's stdin (via `compileall -i -`)
+/// rather than argv, since a large install can easily exceed the OS argv
+/// limit. Failures are collected and logged rather than aborting the
+/// install, matching pip's `--compile` behavior.
+#[cfg(not(feature = "install"))]
+fn compile_pyc(python: &Utf8Path, py_files: &[Utf8PathBuf]) -> anyhow::Result<Vec<Utf8PathBuf>> {
+    use std::process::{Command, Stdio};
+
+    if py_files.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut child = Command::new(python.as_str())
+        .args(["-m", "compileall", "-q", "-i", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to spawn {python} -m compileall"))?;
+
+    let mut stdin = child.stdin.take().context("Child has no stdin")?;
+    let mut stderr = child.stderr.take().context("Child has no stderr")?;
+
+    // Drain stderr on its own thread while we write the (potentially huge)
+    // file list to stdin, so neither side can block the other on a full OS
+    // pipe buffer.
+    let stderr_output = std::thread::scope(|scope| -> anyhow::Result<Vec<u8>> {
+        let stderr_reader = scope.spawn(move || {
+            let mut buf = Vec::new();
+            stderr.read_to_end(&mut buf).map(|_| buf)
+        });
+        for py_file in py_files {
+            writeln!(stdin, "{py_file}")?;
+        }
+        drop(stdin);
+        stderr_reader
+            .join()
+            .map_err(|_| format_err!("compileall stderr reader thread panicked"))?
+            .map_err(anyhow::Error::from)
+    })?;
+
+    let status = child.wait()?;
+    if !status.success() {
+        let stderr_text = String::from_utf8_lossy(&stderr_output);
+        tracing::warn!("compileall reported failures, continuing without them: {stderr_text}");
+    }
+
+    let tag = python_cache_tag(python)?;
+    Ok(py_files
+        .iter()
+        .filter_map(|py_file| {
+            let parent = py_file.parent()?;
+            let module = py_file.file_stem()?;
+            Some(parent.join("__pycache__").join(format!("{module}.{tag}.pyc")))
+        })
+        .filter(|pyc| pyc.is_file())
+        .collect())
+}
+
+/// Ask the interpreter for its bytecode cache tag (e.g. `cpython-311`), used
+/// to name `.pyc` files under `__pycache__` per PEP 3147.
+#[cfg(not(feature = "install"))]
+fn python_cache_tag(python: &Utf8Path) -> anyhow::Result<String> {
+    let output = std::process::Command::new(python.as_str())
+        .args(["-c", "import sys; print(sys.implementation.cache_tag)"])
+        .output()
+        .with_context(|| format!("Failed to run {python}"))?;
+    if !output.status.success() {
+        return Err(format_err!("{python} -c ... failed to determine cache tag"));
+    }
+    Ok(String::from_utf8(output.stdout)?.trim().to_string())
+}
+
+/// Generate launchers for every `console_scripts` and `gui_scripts` entry
+/// point declared in a package's `entry_points.txt`, if it has one.
+///
+/// virtualenv for some reason creates extra entrypoints that we don't:
+/// https://github.com/pypa/virtualenv/blob/025e96fbad37f85617364002ae2a0064b09fc984/src/virtualenv/seed/embed/via_app_data/pip_install/base.py#L74-L95
+#[cfg(not(feature = "install"))]
+fn generate_entry_point_launchers(
+    dist_info: &Utf8Path,
     bin_dir: &Utf8Path,
-    venv_python: &Utf8Path,
-    site_packages: &Utf8Path,
-) -> anyhow::Result<()> {
-    // Install packages
-    // TODO: Implement our own logic:
-    //  * Our own cache and logic to detect whether a wheel is present
-    //  * Check if the version is recent (e.g. update if older than 1 month)
-    //  * Query pypi API if no, parse versions (pep440) and their metadata
-    //  * Download compatible wheel (py3-none-any should do)
-    //  * Install into the cache directory
-    let prefix = "virtualenv/wheel/3.11/image/1/CopyPipInstall/";
-    let wheel_tag = "py3-none-any";
-    let packages = &[
-        ("pip", "23.2.1"),
-        ("setuptools", "68.2.2"),
-        ("wheel", "0.41.2"),
-    ];
-    let virtualenv_data_dir = data_dir()
-        .and_then(|path| Utf8PathBuf::from_path_buf(path).ok())
-        .context("Couldn't get data dir")?;
-    for (name, version) in packages {
-        // TODO: acquire lock
-        let unpacked_wheel = virtualenv_data_dir
-            .join(prefix)
-            .join(format!("{name}-{version}-{wheel_tag}"));
-        debug!("Installing {name} by copying from {unpacked_wheel}");
-        bare::copy_dir_all(&unpacked_wheel, site_packages.as_std_path())
-            .with_context(|| format!("Failed to copy {unpacked_wheel} to {site_packages}"))?;
-
-        // Generate launcher
-        // virtualenv for some reason creates extra entrypoints that we don't
-        // https://github.com/pypa/virtualenv/blob/025e96fbad37f85617364002ae2a0064b09fc984/src/virtualenv/seed/embed/via_app_data/pip_install/base.py#L74-L95
-        let ini_text = fs::read_to_string(
-            site_packages
-                .join(format!("{name}-{version}.dist-info"))
-                .join("entry_points.txt"),
-        )
-        .with_context(|| format!("{name} should have an entry_points.txt"))?;
-        let entry_points_mapping = Ini::new_cs()
-            .read(ini_text)
-            .map_err(|err| format_err!("{name} entry_points.txt is invalid: {}", err))?;
+    python: &Utf8Path,
+) -> anyhow::Result<Vec<Utf8PathBuf>> {
+    let entry_points_path = dist_info.join("entry_points.txt");
+    if !entry_points_path.is_file() {
+        return Ok(Vec::new());
+    }
+    let ini_text = fs::read_to_string(&entry_points_path)
+        .with_context(|| format!("Failed to read {entry_points_path}"))?;
+    let entry_points_mapping = Ini::new_cs()
+        .read(ini_text)
+        .map_err(|err| format_err!("{dist_info} entry_points.txt is invalid: {}", err))?;
+
+    let mut launchers = Vec::new();
+    for (section, gui) in [("console_scripts", false), ("gui_scripts", true)] {
         for (key, value) in entry_points_mapping
-            .get("console_scripts")
+            .get(section)
             .cloned()
             .unwrap_or_default()
         {
@@ -86,18 +545,357 @@ fn install_base_packages(
                 .as_ref()
                 .and_then(|value| value.split_once(':'))
                 .ok_or_else(|| {
-                    format_err!("{name} entry_points.txt {key} has an invalid value {value:?}")
+                    format_err!(
+                        "{dist_info} entry_points.txt {key} has an invalid value {value:?}"
+                    )
                 })?;
-            let launcher = bin_dir.join(key);
-            let launcher_script = bare::unix_launcher_script(venv_python, import_from, function);
-            fs::write(&launcher, launcher_script)?;
-            // We need to make the launcher executable
-            #[cfg(target_family = "unix")]
-            {
-                use std::os::unix::fs::PermissionsExt;
-                fs::set_permissions(launcher, std::fs::Permissions::from_mode(0o755))?;
+            launchers.push(write_launcher(bin_dir, &key, python, import_from, function, gui)?);
+        }
+    }
+    Ok(launchers)
+}
+
+/// Write a single console/GUI-script launcher for `key`, dispatching on
+/// target family: a `#!`-shebang Python script on Unix, or a native stub
+/// with an appended zip payload on Windows. Returns the launcher's path.
+#[cfg(all(not(feature = "install"), target_family = "windows"))]
+fn write_launcher(
+    bin_dir: &Utf8Path,
+    key: &str,
+    python: &Utf8Path,
+    import_from: &str,
+    function: &str,
+    gui: bool,
+) -> anyhow::Result<Utf8PathBuf> {
+    let launcher = bin_dir.join(format!("{key}.exe"));
+    let bytes = windows_launcher(python, import_from, function, gui)?;
+    fs::write(&launcher, bytes)?;
+    Ok(launcher)
+}
+
+/// Unix counterpart of the Windows launcher above: just a `#!`-shebang
+/// Python script, made executable. Returns the launcher's path.
+#[cfg(all(not(feature = "install"), target_family = "unix"))]
+fn write_launcher(
+    bin_dir: &Utf8Path,
+    key: &str,
+    python: &Utf8Path,
+    import_from: &str,
+    function: &str,
+    _gui: bool,
+) -> anyhow::Result<Utf8PathBuf> {
+    let launcher = bin_dir.join(key);
+    let launcher_script = unix_launcher_script(python, import_from, function);
+    fs::write(&launcher, launcher_script)?;
+    // We need to make the launcher executable
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(&launcher, std::fs::Permissions::from_mode(0o755))?;
+    Ok(launcher)
+}
+
+/// Console-script stub: a tiny native launcher binary that, at startup,
+/// reads its own trailing payload (an appended zip file and shebang line)
+/// rather than needing an interpreter to parse a `.py`/`.bat` wrapper. See
+/// distlib's `ScriptMaker`.
+#[cfg(not(feature = "install"))]
+const CLI_LAUNCHER_STUB: &[u8] = include_bytes!("../assets/launcher-cli.exe");
+
+/// Same as [`CLI_LAUNCHER_STUB`], but built without a console window, for
+/// `gui_scripts` entry points.
+#[cfg(not(feature = "install"))]
+const GUI_LAUNCHER_STUB: &[u8] = include_bytes!("../assets/launcher-gui.exe");
+
+/// Check that `stub` looks like a real PE executable (starts with the `MZ`
+/// DOS header magic), so a missing or placeholder stub binary fails loudly
+/// at launcher-generation time instead of silently producing a `.exe` that
+/// cannot run.
+#[cfg(not(feature = "install"))]
+fn check_stub_is_pe(which: &str, stub: &[u8]) -> anyhow::Result<()> {
+    if stub.get(0..2) != Some(&b"MZ"[..]) {
+        return Err(format_err!(
+            "{which} launcher stub in assets/ is not a real PE executable (missing 'MZ' DOS \
+             header). See assets/README.md for where to get a real distlib/pip-style stub \
+             binary before Windows launchers can be generated."
+        ));
+    }
+    Ok(())
+}
+
+/// Build a Windows launcher executable for a console or GUI entry point.
+///
+/// This is the distlib `ScriptMaker` technique: the stub is a tiny native
+/// executable that reads its own trailing zip and shebang, so no `.py`/
+/// `.bat` indirection is needed. The payload is a `__main__.py` that runs
+/// `from {import_from} import {function}; sys.exit({function}())`, and the
+/// trailing shebang points at the venv's python.
+#[cfg(not(feature = "install"))]
+pub fn windows_launcher(
+    python: &Utf8Path,
+    import_from: &str,
+    function: &str,
+    gui: bool,
+) -> anyhow::Result<Vec<u8>> {
+    let (which, stub) = if gui {
+        ("GUI", GUI_LAUNCHER_STUB)
+    } else {
+        ("CLI", CLI_LAUNCHER_STUB)
+    };
+    check_stub_is_pe(which, stub)?;
+
+    let main_py = format!(
+        "import sys\nfrom {import_from} import {function}\nif __name__ == '__main__':\n    sys.exit({function}())\n"
+    );
+
+    let mut zip_payload = Vec::new();
+    {
+        let mut writer = zip::ZipWriter::new(io::Cursor::new(&mut zip_payload));
+        writer.start_file("__main__.py", zip::write::FileOptions::default())?;
+        writer.write_all(main_py.as_bytes())?;
+        writer.finish()?;
+    }
+
+    let mut launcher = Vec::with_capacity(stub.len() + zip_payload.len() + python.as_str().len());
+    launcher.extend_from_slice(stub);
+    launcher.extend_from_slice(&zip_payload);
+    launcher.extend_from_slice(format!("#!{python}\n").as_bytes());
+    Ok(launcher)
+}
+
+/// Write `{dist_info}/RECORD`, the manifest pip and other installers use to
+/// verify and later uninstall a package's files: one `path,sha256=...,size`
+/// row per file, relative to the install root.
+#[cfg(not(feature = "install"))]
+fn write_record(dist_info: &Utf8Path, root: &Utf8Path, installed: &[RecordEntry]) -> anyhow::Result<()> {
+    let record_path = dist_info.join("RECORD");
+
+    // RECORD is RFC 4180 CSV, so paths containing commas/quotes (legal in a
+    // zip archive) need real quoting rather than hand-rolled string joins.
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(Vec::new());
+    for entry in installed {
+        match (&entry.hash, entry.size) {
+            (Some(hash), Some(size)) => {
+                writer.write_record([
+                    entry.path.as_str(),
+                    &format!("sha256={hash}"),
+                    &size.to_string(),
+                ])?;
+            }
+            // Launcher scripts get empty hash/size fields, same as RECORD itself below.
+            _ => writer.write_record([entry.path.as_str(), "", ""])?,
+        }
+    }
+    let record_relative = root_relative(root, &record_path);
+    writer.write_record([record_relative.as_str(), "", ""])?;
+    let bytes = writer
+        .into_inner()
+        .map_err(|err| format_err!("Failed to flush RECORD csv writer: {err}"))?;
+
+    fs::write(&record_path, bytes).with_context(|| format!("Failed to write {record_path}"))?;
+    Ok(())
+}
+
+/// How long a resolved seed package version is trusted before we re-query
+/// PyPI for a newer one.
+#[cfg(not(feature = "install"))]
+const SEED_CACHE_FRESHNESS: u64 = 30 * 24 * 60 * 60;
+
+/// A seed package release resolved from PyPI: the concrete version picked,
+/// the wheel's download URL and expected hash, cached alongside the time it
+/// was resolved so offline installs can keep working from a stale cache.
+#[cfg(not(feature = "install"))]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ResolvedSeed {
+    version: String,
+    url: String,
+    sha256: String,
+    resolved_at: u64,
+}
+
+#[cfg(not(feature = "install"))]
+#[derive(Deserialize)]
+struct PypiProject {
+    releases: HashMap<String, Vec<PypiFile>>,
+}
+
+#[cfg(not(feature = "install"))]
+#[derive(Deserialize)]
+struct PypiFile {
+    filename: String,
+    url: String,
+    #[serde(default)]
+    yanked: bool,
+    digests: PypiDigests,
+}
+
+#[cfg(not(feature = "install"))]
+#[derive(Deserialize)]
+struct PypiDigests {
+    sha256: String,
+}
+
+/// The python/abi/platform tags a wheel filename declares, e.g.
+/// `pip-23.2.1-py3-none-any.whl` has python tags `[py3]`, abi tags `[none]`
+/// and platform tags `[any]`. Tag compressions (`py2.py3-none-any`) expand
+/// to multiple tags in the same slot.
+#[cfg(not(feature = "install"))]
+struct WheelTags {
+    python_tags: Vec<String>,
+    abi_tags: Vec<String>,
+    platform_tags: Vec<String>,
+}
+
+#[cfg(not(feature = "install"))]
+fn parse_wheel_tags(filename: &str) -> Option<WheelTags> {
+    let stem = filename.strip_suffix(".whl")?;
+    let parts: Vec<&str> = stem.split('-').collect();
+    if parts.len() < 5 {
+        return None;
+    }
+    let mut tags = parts[parts.len() - 3..].iter().map(|tag| {
+        tag.split('.').map(str::to_string).collect::<Vec<_>>()
+    });
+    Some(WheelTags {
+        python_tags: tags.next()?,
+        abi_tags: tags.next()?,
+        platform_tags: tags.next()?,
+    })
+}
+
+/// Pick a `none-any` wheel file compatible with `python_version` from a
+/// PyPI release's file list.
+///
+/// This only matches pure-Python (`none`-abi, `any`-platform) wheels, which
+/// is all the seed packages this is used for (pip, setuptools, wheel)
+/// publish — it is not a general platform-tag compatibility resolver (no
+/// ABI tag or platform tag, e.g. `manylinux`, matching), and will return
+/// `None` for a release that only ships platform-specific wheels.
+#[cfg(not(feature = "install"))]
+fn pick_pure_python_wheel(files: &[PypiFile], python_version: (u8, u8)) -> Option<&PypiFile> {
+    let (major, minor) = python_version;
+    let python_candidates = [
+        format!("py{major}"),
+        format!("py{major}{minor}"),
+        format!("cp{major}{minor}"),
+    ];
+
+    let candidates: Vec<&PypiFile> = files
+        .iter()
+        .filter(|file| !file.yanked)
+        .filter_map(|file| Some((file, parse_wheel_tags(&file.filename)?)))
+        .filter(|(_, tags)| {
+            tags.python_tags
+                .iter()
+                .any(|tag| python_candidates.contains(tag))
+                && tags.abi_tags.iter().any(|tag| tag == "none")
+                && tags.platform_tags.iter().any(|tag| tag == "any")
+        })
+        .map(|(file, _)| file)
+        .collect();
+    candidates.into_iter().next()
+}
+
+/// Query `https://pypi.org/pypi/{name}/json`, sort releases by PEP 440
+/// version and return the newest one that publishes a pure-Python
+/// (`none-any`) wheel compatible with `python_version` — see
+/// [`pick_pure_python_wheel`] for the scope of that check.
+#[cfg(not(feature = "install"))]
+fn resolve_pypi_wheel(name: &str, python_version: (u8, u8)) -> anyhow::Result<ResolvedSeed> {
+    let url = format!("https://pypi.org/pypi/{name}/json");
+    let body = minreq::get(&url)
+        .send()
+        .with_context(|| format!("Failed to query {url}"))?
+        .into_bytes();
+    let project: PypiProject =
+        serde_json::from_slice(&body).with_context(|| format!("{url} returned invalid JSON"))?;
+
+    let mut versions: Vec<(String, Pep440Version)> = project
+        .releases
+        .keys()
+        .filter_map(|raw| Pep440Version::from_str(raw).ok().map(|v| (raw.clone(), v)))
+        .collect();
+    versions.sort_by(|a, b| a.1.cmp(&b.1));
+
+    for (raw, _) in versions.into_iter().rev() {
+        let Some(file) = pick_pure_python_wheel(&project.releases[&raw], python_version) else {
+            continue;
+        };
+        return Ok(ResolvedSeed {
+            version: raw,
+            url: file.url.clone(),
+            sha256: file.digests.sha256.clone(),
+            resolved_at: unix_now()?,
+        });
+    }
+    Err(format_err!(
+        "No wheel on PyPI for {name} is compatible with py{}.{}",
+        python_version.0,
+        python_version.1
+    ))
+}
+
+#[cfg(not(feature = "install"))]
+fn unix_now() -> anyhow::Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+#[cfg(not(feature = "install"))]
+fn seed_cache_path(name: &str) -> anyhow::Result<Utf8PathBuf> {
+    Ok(crate::crate_cache_dir()?.join("seeds").join(format!("{name}.json")))
+}
+
+/// Resolve the wheel to install for seed package `name`, from a fresh cache
+/// entry if there is one, otherwise from PyPI (falling back to a stale cache
+/// entry if PyPI can't be reached, so offline installs keep working).
+#[cfg(not(feature = "install"))]
+fn resolve_seed_wheel(name: &str, python_version: (u8, u8)) -> anyhow::Result<ResolvedSeed> {
+    let cache_path = seed_cache_path(name)?;
+    let cached = cache_path
+        .is_file()
+        .then(|| -> anyhow::Result<ResolvedSeed> {
+            Ok(serde_json::from_str(&fs::read_to_string(&cache_path)?)?)
+        })
+        .transpose()?;
+    if let Some(cached) = &cached {
+        if unix_now()?.saturating_sub(cached.resolved_at) < SEED_CACHE_FRESHNESS {
+            return Ok(cached.clone());
+        }
+    }
+
+    match resolve_pypi_wheel(name, python_version) {
+        Ok(resolved) => {
+            if let Some(parent) = cache_path.parent() {
+                fs::create_dir_all(parent)?;
             }
+            fs::write(&cache_path, serde_json::to_string_pretty(&resolved)?)?;
+            Ok(resolved)
         }
+        Err(err) => match cached {
+            Some(cached) => {
+                info!("Failed to query PyPI for {name}, using stale cache: {err:#}");
+                Ok(cached)
+            }
+            None => Err(err),
+        },
+    }
+}
+
+/// Install wheel, pip and setuptools, resolving the newest compatible
+/// release from PyPI for the given `python_version`.
+#[cfg(not(feature = "install"))]
+fn install_base_packages(paths: &InstallPaths, python_version: (u8, u8)) -> anyhow::Result<()> {
+    for name in ["pip", "setuptools", "wheel"] {
+        // TODO: acquire lock
+        let seed = resolve_seed_wheel(name, python_version)?;
+        let filename = seed
+            .url
+            .rsplit('/')
+            .next()
+            .with_context(|| format!("{} is not a valid wheel URL", seed.url))?;
+        let wheel_path = download_wheel_cached(filename, &seed.url, Some(&seed.sha256))?;
+        install_wheel(&wheel_path, paths, true)
+            .with_context(|| format!("Failed to install {name} {}", seed.version))?;
     }
     Ok(())
 }
@@ -136,3 +934,161 @@ if __name__ == '__main__':
         function = function
     )
 }
+
+#[cfg(all(not(feature = "install"), test))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_stub_is_pe_rejects_placeholder_text() {
+        assert!(check_stub_is_pe("CLI", b"placeholder-cli-launcher-stub").is_err());
+    }
+
+    #[test]
+    fn check_stub_is_pe_accepts_mz_header() {
+        assert!(check_stub_is_pe("CLI", b"MZ\x90\x00\x03\x00\x00\x00").is_ok());
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn root_relative_normalizes_windows_separators() {
+        let root = Utf8Path::new(r"C:\venv");
+        let path = Utf8PathBuf::from(r"C:\venv\Lib\site-packages\foo.py");
+        assert_eq!(root_relative(root, &path).as_str(), "Lib/site-packages/foo.py");
+    }
+
+    #[test]
+    fn record_path_to_fs_roundtrips_through_root_relative() {
+        let root = Utf8Path::new("/venv");
+        let path = Utf8PathBuf::from("/venv/lib/python3.11/site-packages/foo.py");
+        let relative = root_relative(root, &path);
+        assert_eq!(record_path_to_fs(root, relative.as_str()), path);
+    }
+
+    #[test]
+    fn wheel_target_routes_data_subdir_to_scheme_dir() {
+        let paths = InstallPaths::new(
+            Utf8Path::new("/venv"),
+            Utf8Path::new("/venv/bin/python3"),
+            (3, 11),
+            Platform::Unix,
+        );
+        let target = wheel_target(
+            &paths,
+            "foo-1.0.data",
+            Utf8Path::new("foo-1.0.data/scripts/foo"),
+        )
+        .unwrap();
+        assert_eq!(target, paths.scripts.join("foo"));
+    }
+
+    #[test]
+    fn encode_digest_matches_known_sha256_of_empty_input() {
+        let hasher = Sha256::new();
+        assert_eq!(encode_digest(hasher), "47DEQpj8HBSa-_TImW-5JCeuQeRkm5NMpJWZG3hSuFU");
+    }
+
+    #[test]
+    fn encode_digest_has_no_padding_and_is_url_safe() {
+        let mut hasher = Sha256::new();
+        hasher.update(b"hello world");
+        let digest = encode_digest(hasher);
+        assert_eq!(digest, "uU0nuZNNPgilLlLX2n2r-sSE7-N6U4DukIj3rOLvzek");
+        assert!(!digest.contains(['+', '/', '=']));
+    }
+
+    #[test]
+    fn record_csv_quotes_paths_with_commas() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = Utf8Path::from_path(dir.path()).unwrap();
+        let dist_info = root.join("foo-1.0.dist-info");
+        fs::create_dir_all(&dist_info).unwrap();
+
+        let tricky = root.join("weird, name.py");
+        fs::write(&tricky, "x").unwrap();
+
+        write_record(
+            &dist_info,
+            root,
+            &[RecordEntry {
+                path: root_relative(root, &tricky),
+                hash: Some("deadbeef".to_string()),
+                size: Some(1),
+            }],
+        )
+        .unwrap();
+
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_path(dist_info.join("RECORD"))
+            .unwrap();
+        let records: Vec<_> = reader.records().collect::<Result<_, _>>().unwrap();
+        assert_eq!(records[0].get(0), Some("weird, name.py"));
+        assert_eq!(records[0].get(1), Some("sha256=deadbeef"));
+    }
+
+    #[test]
+    fn wheel_target_defaults_to_purelib() {
+        let paths = InstallPaths::new(
+            Utf8Path::new("/venv"),
+            Utf8Path::new("/venv/bin/python3"),
+            (3, 11),
+            Platform::Unix,
+        );
+        let target = wheel_target(
+            &paths,
+            "foo-1.0.data",
+            Utf8Path::new("foo/__init__.py"),
+        )
+        .unwrap();
+        assert_eq!(target, paths.purelib.join("foo/__init__.py"));
+    }
+
+    #[test]
+    fn parse_wheel_tags_expands_compressed_tags() {
+        let tags = parse_wheel_tags("pip-23.2.1-py2.py3-none-any.whl").unwrap();
+        assert_eq!(tags.python_tags, vec!["py2", "py3"]);
+        assert_eq!(tags.abi_tags, vec!["none"]);
+        assert_eq!(tags.platform_tags, vec!["any"]);
+    }
+
+    #[test]
+    fn parse_wheel_tags_rejects_non_wheel_filenames() {
+        assert!(parse_wheel_tags("pip-23.2.1.tar.gz").is_none());
+    }
+
+    fn pypi_file(filename: &str) -> PypiFile {
+        PypiFile {
+            filename: filename.to_string(),
+            url: format!("https://example.invalid/{filename}"),
+            yanked: false,
+            digests: PypiDigests {
+                sha256: "0".repeat(64),
+            },
+        }
+    }
+
+    #[test]
+    fn pick_pure_python_wheel_matches_none_any() {
+        let files = [
+            pypi_file("foo-1.0-cp311-cp311-manylinux_2_17_x86_64.whl"),
+            pypi_file("foo-1.0-py3-none-any.whl"),
+        ];
+        let picked = pick_pure_python_wheel(&files, (3, 11)).unwrap();
+        assert_eq!(picked.filename, "foo-1.0-py3-none-any.whl");
+    }
+
+    #[test]
+    fn pick_pure_python_wheel_ignores_yanked_files() {
+        let mut only_file = pypi_file("foo-1.0-py3-none-any.whl");
+        only_file.yanked = true;
+        assert!(pick_pure_python_wheel(&[only_file], (3, 11)).is_none());
+    }
+
+    #[test]
+    fn pick_pure_python_wheel_returns_none_for_platform_specific_only_release() {
+        let files = [pypi_file("foo-1.0-cp311-cp311-manylinux_2_17_x86_64.whl")];
+        assert!(pick_pure_python_wheel(&files, (3, 11)).is_none());
+    }
+}